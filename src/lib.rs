@@ -1,89 +1,413 @@
-use std::alloc::{alloc, dealloc, realloc, Layout};
-use std::collections::HashMap;
+use std::alloc::{alloc, dealloc, handle_alloc_error, realloc, Layout};
 use std::io::{self, Read, Write};
 use std::ops::{Deref, DerefMut};
 use std::ptr;
 use std::sync::{Arc, Mutex};
 
+/// Buffers at or above this size are backed by `mmap` instead of the heap
+/// allocator, since the 16K/32K (and bigger) size classes are where a
+/// `realloc`-driven `grow` starts costing a real copy.
+const MMAP_THRESHOLD: usize = 16 * 1024;
+
+/// Allocate `layout` via the global allocator, aborting the process through
+/// `handle_alloc_error` on failure instead of returning a null pointer --
+/// the same contract `RawVec` relies on.
+fn safe_alloc(layout: Layout) -> *mut u8 {
+    unsafe {
+        let ptr = alloc(layout);
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        ptr
+    }
+}
+
+/// `realloc` the allocation described by `old_layout` to `new_size`,
+/// aborting on failure rather than returning null.
+fn safe_realloc(ptr: *mut u8, old_layout: Layout, new_size: usize) -> *mut u8 {
+    unsafe {
+        let new_ptr = realloc(ptr, old_layout, new_size);
+        if new_ptr.is_null() {
+            handle_alloc_error(Layout::from_size_align(new_size, old_layout.align()).unwrap());
+        }
+        new_ptr
+    }
+}
+
+/// Backing storage for an `MBuf`. A backend owns a contiguous region of
+/// memory and knows how to grow it; `MBuf` itself is agnostic to whether
+/// that memory came from the heap or from an anonymous mapping.
+pub trait MemoryBackend {
+    /// Pointer to the start of the backing storage.
+    fn as_ptr(&self) -> *mut u8;
+
+    /// Total capacity currently available without a further `grow`.
+    fn capacity(&self) -> usize;
+
+    /// Grow the backing storage so that `capacity() >= new_capacity`.
+    fn grow(&mut self, new_capacity: usize) -> io::Result<()>;
+}
+
+/// Backend that goes through the global allocator's `alloc`/`realloc`.
 #[derive(Debug)]
-pub struct MBuf {
-    buffer: *mut u8,  // Raw pointer to the start of the buffer
-    data_len: usize,  // Length of the valid data in the buffer
-    buffer_len: usize, // Total length of the buffer
-    pos: usize,       // Current read/write position within the buffer
+struct HeapBuf {
+    ptr: *mut u8,
+    capacity: usize,
 }
 
-impl MBuf {
-    pub fn new(buffer_len: usize) -> Self {
+impl HeapBuf {
+    fn new(capacity: usize) -> Self {
+        // `alloc`/`dealloc`/`realloc` are documented UB on a zero-size
+        // `Layout`, so a zero-capacity buffer holds a dangling, non-null
+        // pointer instead of touching the allocator at all.
+        let ptr = if capacity == 0 {
+            ptr::NonNull::dangling().as_ptr()
+        } else {
+            let layout = Layout::from_size_align(capacity, 8).unwrap();
+            safe_alloc(layout)
+        };
+        HeapBuf { ptr, capacity }
+    }
+}
+
+impl MemoryBackend for HeapBuf {
+    fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn grow(&mut self, new_capacity: usize) -> io::Result<()> {
+        self.ptr = if self.capacity == 0 {
+            let layout = Layout::from_size_align(new_capacity, 8).unwrap();
+            safe_alloc(layout)
+        } else {
+            let layout = Layout::from_size_align(self.capacity, 8).unwrap();
+            safe_realloc(self.ptr, layout, new_capacity)
+        };
+        self.capacity = new_capacity;
+        Ok(())
+    }
+}
+
+impl Drop for HeapBuf {
+    fn drop(&mut self) {
+        if self.capacity == 0 {
+            return;
+        }
         unsafe {
-            let layout = Layout::from_size_align(buffer_len, 8).unwrap();
-            let buffer = alloc(layout) as *mut u8;
-            if buffer.is_null() {
-                panic!("Failed to allocate buffer");
-            }
+            let layout = Layout::from_size_align(self.capacity, 8).unwrap();
+            dealloc(self.ptr, layout);
+        }
+    }
+}
 
-            MBuf {
-                buffer,
-                data_len: 0,
-                buffer_len,
-                pos: 0,
+/// Backend that reserves address space with an anonymous `mmap` and grows
+/// via `mremap`, so large buffers don't pay a touch-all-pages or copy cost
+/// just to get bigger.
+#[derive(Debug)]
+struct MmapBuf {
+    ptr: *mut u8,
+    capacity: usize,
+}
+
+impl MmapBuf {
+    fn new(capacity: usize) -> io::Result<Self> {
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                capacity,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
             }
+            Ok(MmapBuf {
+                ptr: ptr as *mut u8,
+                capacity,
+            })
         }
     }
+}
 
-    fn grow(&mut self, additional: usize) -> Result<(), io::Error> {
-        let new_buffer_len = self.buffer_len + additional;
+impl MemoryBackend for MmapBuf {
+    fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn grow(&mut self, new_capacity: usize) -> io::Result<()> {
         unsafe {
-            let layout = Layout::from_size_align(self.buffer_len, 8).unwrap();
-            let new_buffer = realloc(self.buffer, layout, new_buffer_len) as *mut u8;
-            if new_buffer.is_null() {
+            let ptr = libc::mremap(
+                self.ptr as *mut libc::c_void,
+                self.capacity,
+                new_capacity,
+                libc::MREMAP_MAYMOVE,
+            );
+            if ptr == libc::MAP_FAILED {
                 return Err(io::Error::last_os_error());
             }
-
-            self.buffer = new_buffer;
-            self.buffer_len = new_buffer_len;
+            self.ptr = ptr as *mut u8;
+            self.capacity = new_capacity;
             Ok(())
         }
     }
+}
+
+impl Drop for MmapBuf {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.capacity);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Backend {
+    Heap(HeapBuf),
+    Mmap(MmapBuf),
+}
+
+// Safety: a `Backend` exclusively owns the memory its pointer refers to
+// (it's never aliased by another `Backend`), so moving one to another
+// thread, or accessing it only through `&mut self`/a `Mutex`-serialized
+// `&self` as `MBufPool`/`PooledMBuf` do, is exactly as sound as it would be
+// for an owned `Vec<u8>`. There's nothing thread-affine about a raw heap
+// or mmap allocation itself.
+unsafe impl Send for Backend {}
+unsafe impl Sync for Backend {}
+
+impl MemoryBackend for Backend {
+    fn as_ptr(&self) -> *mut u8 {
+        match self {
+            Backend::Heap(b) => b.as_ptr(),
+            Backend::Mmap(b) => b.as_ptr(),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        match self {
+            Backend::Heap(b) => b.capacity(),
+            Backend::Mmap(b) => b.capacity(),
+        }
+    }
+
+    fn grow(&mut self, new_capacity: usize) -> io::Result<()> {
+        match self {
+            Backend::Heap(b) => b.grow(new_capacity),
+            Backend::Mmap(b) => b.grow(new_capacity),
+        }
+    }
+}
+
+/// Selects which `MemoryBackend` a new `MBuf` should use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Heap,
+    Mmap,
+}
+
+#[derive(Debug)]
+pub struct MBuf {
+    backend: Backend,
+    data_len: usize, // Length of the valid data in the buffer
+    pos: usize,      // Current read/write position within the buffer
+    headroom: usize, // Reserved bytes before the data, free for `prepend`
+}
+
+impl MBuf {
+    /// Allocate a new buffer, picking a backend automatically: buffers at
+    /// or above `MMAP_THRESHOLD` use `mmap`, smaller ones use the heap.
+    pub fn new(buffer_len: usize) -> Self {
+        let kind = if buffer_len >= MMAP_THRESHOLD {
+            BackendKind::Mmap
+        } else {
+            BackendKind::Heap
+        };
+        Self::with_backend(buffer_len, kind)
+    }
+
+    /// Allocate a new buffer with an explicitly chosen backend.
+    pub fn with_backend(buffer_len: usize, kind: BackendKind) -> Self {
+        let backend = match kind {
+            BackendKind::Heap => Backend::Heap(HeapBuf::new(buffer_len)),
+            BackendKind::Mmap => {
+                Backend::Mmap(MmapBuf::new(buffer_len).expect("Failed to mmap buffer"))
+            }
+        };
+
+        MBuf {
+            backend,
+            data_len: 0,
+            pos: 0,
+            headroom: 0,
+        }
+    }
+
+    /// Allocate a buffer with room for at least `capacity` bytes without
+    /// needing to grow, without appending any data to it.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::new(capacity)
+    }
+
+    /// Allocate a `buffer_len`-byte buffer with `headroom` bytes reserved
+    /// in front of the data area, so protocol headers can later be
+    /// `prepend`ed without shifting the payload.
+    pub fn with_headroom(buffer_len: usize, headroom: usize) -> Self {
+        assert!(headroom <= buffer_len, "headroom cannot exceed buffer_len");
+        let mut mbuf = Self::new(buffer_len);
+        mbuf.headroom = headroom;
+        mbuf
+    }
+
+    fn buffer(&self) -> *mut u8 {
+        self.backend.as_ptr()
+    }
+
+    fn buffer_len(&self) -> usize {
+        self.backend.capacity()
+    }
+
+    /// Pointer to the start of the data area, i.e. `buffer + headroom`.
+    fn data_start(&self) -> *mut u8 {
+        unsafe { self.buffer().add(self.headroom) }
+    }
+
+    /// Total capacity of the backing storage.
+    pub fn capacity(&self) -> usize {
+        self.buffer_len()
+    }
+
+    /// Bytes currently reserved in front of the data for `prepend`.
+    pub fn headroom(&self) -> usize {
+        self.headroom
+    }
+
+    /// Bytes free after the data and before the end of the buffer.
+    pub fn tailroom(&self) -> usize {
+        self.buffer_len() - self.headroom - self.data_len
+    }
+
+    /// Ensure the buffer can hold `additional` more bytes without a further
+    /// grow, reallocating now if it can't.
+    pub fn reserve(&mut self, additional: usize) {
+        if additional > self.tailroom() {
+            self.grow(additional).expect("failed to grow buffer");
+        }
+    }
+
+    /// Grow the backing storage so it has room for `additional` more bytes
+    /// of tailroom, at least doubling capacity so repeated small grows stay
+    /// amortized O(1) rather than paying for a realloc on every call.
+    fn grow(&mut self, additional: usize) -> Result<(), io::Error> {
+        let needed = self.headroom + self.data_len + additional;
+        let new_buffer_len = std::cmp::max(self.buffer_len() * 2, needed);
+        self.backend.grow(new_buffer_len)
+    }
 
     pub fn append(&mut self, data: &[u8]) -> Result<(), io::Error> {
-        if self.data_len + data.len() > self.buffer_len {
-           let result =  self.grow(data.len());
-           if result.is_err() {
-               return result;
-           }
+        if data.len() > self.tailroom() {
+            self.grow(data.len())?;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), self.data_start().add(self.data_len), data.len());
+        }
+        self.data_len += data.len();
+        Ok(())
+    }
+
+    /// Prepend `data` into the reserved headroom, growing the data area
+    /// backward with no copy of the existing payload. Errors if there
+    /// isn't enough headroom to hold `data`.
+    pub fn prepend(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        if data.len() > self.headroom {
+            return Err(io::Error::other("not enough headroom to prepend"));
         }
 
+        self.headroom -= data.len();
         unsafe {
-            ptr::copy_nonoverlapping(data.as_ptr(), self.buffer.add(self.data_len), data.len());
+            ptr::copy_nonoverlapping(data.as_ptr(), self.data_start(), data.len());
         }
         self.data_len += data.len();
         Ok(())
     }
 
+    /// Strip `n` bytes off the front of the data, returning them to the
+    /// headroom (like DPDK's `rte_pktmbuf_adj`).
+    pub fn adj(&mut self, n: usize) -> Result<(), io::Error> {
+        if n > self.data_len {
+            return Err(io::Error::other("cannot adjust past the end of the data"));
+        }
+
+        self.headroom += n;
+        self.data_len -= n;
+        self.pos = self.pos.saturating_sub(n);
+        Ok(())
+    }
+
+    /// Strip `n` bytes off the end of the data (like DPDK's
+    /// `rte_pktmbuf_trim`).
+    pub fn trim(&mut self, n: usize) -> Result<(), io::Error> {
+        if n > self.data_len {
+            return Err(io::Error::other("cannot trim past the start of the data"));
+        }
+
+        self.data_len -= n;
+        self.pos = std::cmp::min(self.pos, self.data_len);
+        Ok(())
+    }
+
     pub fn data_mut(&mut self) -> &mut [u8] {
-        unsafe { std::slice::from_raw_parts_mut(self.buffer, self.data_len) }
+        unsafe { std::slice::from_raw_parts_mut(self.data_start(), self.data_len) }
     }
 
     pub fn data(&self) -> &[u8] {
-        unsafe { std::slice::from_raw_parts(self.buffer, self.data_len) }
+        unsafe { std::slice::from_raw_parts(self.data_start(), self.data_len) }
     }
 
     pub fn set_data(&mut self, data: &[u8]) {
-        if data.len() > self.buffer_len {
+        if data.len() > self.buffer_len() - self.headroom {
             panic!("Data length exceeds buffer capacity");
         }
 
         unsafe {
-            ptr::copy_nonoverlapping(data.as_ptr(), self.buffer, data.len());
+            ptr::copy_nonoverlapping(data.as_ptr(), self.data_start(), data.len());
         }
         self.data_len = data.len();
         self.pos = 0;  // Reset position after writing data
     }
 
+    /// Reset the buffer to empty, also dropping any reserved headroom --
+    /// callers that need headroom preserved across reuse should re-apply it
+    /// with `with_headroom`-style bookkeeping after clearing.
     pub fn clear(&mut self) {
         self.data_len = 0;
         self.pos = 0;
+        self.headroom = 0;
+    }
+
+    /// Convert this uniquely-owned, mutable buffer into a `SharedBuf`: an
+    /// `Arc`-backed immutable view over the same allocation. The
+    /// allocation is only freed once every `SharedBuf`/split produced from
+    /// it has been dropped.
+    pub fn freeze(self) -> SharedBuf {
+        let offset = self.headroom;
+        let len = self.data_len;
+        let MBuf { backend, .. } = self;
+        SharedBuf {
+            shared: Arc::new(Shared { backend }),
+            offset,
+            len,
+        }
     }
 }
 
@@ -95,76 +419,463 @@ impl Deref for MBuf {
     }
 }
 
-pub struct MBufPool {
-    pool: HashMap<usize, Vec<MBuf>>,
+/// Cursor-style read/write API modeled on the `bytes` crate's `Buf`/`BufMut`
+/// traits, built on top of `pos`: reads consume from `pos` forward, writes
+/// append to the end, so an `MBuf` can act as a single growable streaming
+/// buffer rather than a bare byte bag.
+impl MBuf {
+    /// Bytes left to read starting at the current position.
+    pub fn remaining(&self) -> usize {
+        self.data_len - self.pos
+    }
+
+    /// The unread slice, from the current position to the end of the data.
+    pub fn chunk(&self) -> &[u8] {
+        &self.data()[self.pos..]
+    }
+
+    /// Advance the read position by `cnt` bytes.
+    pub fn advance(&mut self, cnt: usize) {
+        assert!(cnt <= self.remaining(), "cannot advance past the end of the buffer");
+        self.pos += cnt;
+    }
+
+    /// Copy `dst.len()` bytes from the current position into `dst`,
+    /// advancing past them.
+    pub fn copy_to_slice(&mut self, dst: &mut [u8]) {
+        let n = dst.len();
+        dst.copy_from_slice(&self.chunk()[..n]);
+        self.advance(n);
+    }
+
+    pub fn get_u16(&mut self) -> u16 {
+        let mut buf = [0u8; 2];
+        self.copy_to_slice(&mut buf);
+        u16::from_be_bytes(buf)
+    }
+
+    pub fn get_u32(&mut self) -> u32 {
+        let mut buf = [0u8; 4];
+        self.copy_to_slice(&mut buf);
+        u32::from_be_bytes(buf)
+    }
+
+    pub fn get_u64(&mut self) -> u64 {
+        let mut buf = [0u8; 8];
+        self.copy_to_slice(&mut buf);
+        u64::from_be_bytes(buf)
+    }
+
+    /// Append `src` to the end of the buffer, growing if needed.
+    pub fn put_slice(&mut self, src: &[u8]) {
+        self.append(src).expect("failed to grow buffer");
+    }
+
+    pub fn put_u16(&mut self, n: u16) {
+        self.put_slice(&n.to_be_bytes());
+    }
+
+    pub fn put_u32(&mut self, n: u32) {
+        self.put_slice(&n.to_be_bytes());
+    }
+
+    pub fn put_u64(&mut self, n: u64) {
+        self.put_slice(&n.to_be_bytes());
+    }
+
+    /// Read a `T` out of the current position as raw bytes, advancing past
+    /// it. `T` must be `SizeOf` -- `repr(C)`, no padding/invalid bit
+    /// patterns, no pointers -- for this to be sound.
+    pub fn read_header<T: SizeOf>(&mut self) -> T {
+        let size = std::mem::size_of::<T>();
+        assert!(size <= self.remaining(), "not enough data to read header");
+        let header = unsafe { ptr::read_unaligned(self.chunk().as_ptr() as *const T) };
+        self.advance(size);
+        header
+    }
+
+    /// Append `header`'s raw bytes to the end of the buffer, growing if
+    /// needed.
+    pub fn write_header<T: SizeOf>(&mut self, header: &T) {
+        let size = std::mem::size_of::<T>();
+        let bytes = unsafe { std::slice::from_raw_parts(header as *const T as *const u8, size) };
+        self.put_slice(bytes);
+    }
 }
 
-impl MBufPool {
-    pub fn new() -> Self {
-        MBufPool {
-            pool: HashMap::new(),
-        }
+/// Marker for fixed-size POD structs that can be read or written directly
+/// against buffer bytes via `read_header`/`write_header`, modeled on
+/// wasmi's `SizeOf`.
+///
+/// # Safety
+///
+/// Implementors must be `repr(C)`, free of padding and invalid bit
+/// patterns, and contain no pointers or references -- the
+/// same contract as `bytemuck::Pod`. Violating this makes `read_header`
+/// able to conjure an invalid `T` out of arbitrary buffer bytes.
+pub unsafe trait SizeOf: Copy {}
+
+unsafe impl SizeOf for u8 {}
+unsafe impl SizeOf for u16 {}
+unsafe impl SizeOf for u32 {}
+unsafe impl SizeOf for u64 {}
+
+impl Read for MBuf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), self.remaining());
+        buf[..n].copy_from_slice(&self.chunk()[..n]);
+        self.advance(n);
+        Ok(n)
     }
+}
 
-    pub fn initialize(&mut self) {
-        //add 1k size
-        for _ in 0..100 {
-            self.pool.insert(1024, vec![MBuf::new(1024)]);
-        }
+impl Write for MBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sentinel `StoreAddr` that never refers to a live slot.
+pub const INVALID_ADDR: StoreAddr = StoreAddr {
+    pool_idx: u16::MAX,
+    packet_idx: u16::MAX,
+};
+
+/// Opaque handle into an `MBufPool`, packing a subpool index and a slot
+/// index within that subpool. Cheap to copy and store, unlike an owned
+/// `MBuf`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreAddr {
+    pool_idx: u16,
+    packet_idx: u16,
+}
+
+impl StoreAddr {
+    /// Pack this address into a single `u32`, e.g. for use as a map key or
+    /// over the wire.
+    pub fn raw(&self) -> u32 {
+        ((self.pool_idx as u32) << 16) | self.packet_idx as u32
+    }
+
+    /// `true` unless this is `INVALID_ADDR`. Every `MBufPool` with fewer
+    /// than `u16::MAX` subpools is guaranteed to reject `INVALID_ADDR` as
+    /// out of range, so it's safe to use as a placeholder for "no buffer
+    /// yet" in a field that's only later assigned a real address.
+    pub fn is_valid(&self) -> bool {
+        *self != INVALID_ADDR
+    }
+}
+
+impl Default for StoreAddr {
+    /// Defaults to `INVALID_ADDR`, so a `StoreAddr` field left unassigned
+    /// reads as invalid rather than aliasing slot `(0, 0)`.
+    fn default() -> Self {
+        INVALID_ADDR
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// The requested size doesn't fit any configured subpool.
+    DataTooLarge(usize),
+    /// The subpool (by index) that would fit the request has no free slots.
+    StoreFull(usize),
+    /// The `StoreAddr` doesn't name a subpool/slot that exists.
+    InvalidStoreId,
+    /// The `StoreAddr` is well-formed but its slot isn't currently occupied.
+    DataDoesNotExist,
+}
+
+/// Configuration for an `MBufPool`: a set of `(num_buckets, block_size)`
+/// subpools, sanitized into ascending order by block size so the pool can
+/// pick the smallest subpool that fits a given request.
+#[derive(Debug, Clone)]
+pub struct PoolCfg {
+    buckets: Vec<(usize, usize)>,
+}
 
-        for _ in 0..100 {
-            self.pool.insert(2048, vec![MBuf::new(2048)]);
+impl PoolCfg {
+    /// Sort `buckets` ascending by block size, merging the bucket counts of
+    /// any entries that share a block size rather than discarding them.
+    pub fn new(mut buckets: Vec<(usize, usize)>) -> Self {
+        buckets.sort_by_key(|&(_num_buckets, block_size)| block_size);
+
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(buckets.len());
+        for (num_buckets, block_size) in buckets {
+            match merged.last_mut() {
+                Some((prev_num_buckets, prev_block_size)) if *prev_block_size == block_size => {
+                    *prev_num_buckets += num_buckets;
+                }
+                _ => merged.push((num_buckets, block_size)),
+            }
         }
 
-        for _ in 0..20 {
-            self.pool.insert(4096, vec![MBuf::new(4096)]);
+        PoolCfg { buckets: merged }
+    }
+}
+
+struct Subpool {
+    block_size: usize,
+    slots: Vec<MBuf>,
+    occupied: Vec<bool>,
+    free_list: Vec<u16>,
+}
+
+impl Subpool {
+    fn new(num_buckets: usize, block_size: usize) -> Self {
+        Subpool {
+            block_size,
+            slots: (0..num_buckets).map(|_| MBuf::new(block_size)).collect(),
+            occupied: vec![false; num_buckets],
+            free_list: (0..num_buckets as u16).rev().collect(),
         }
+    }
+}
+
+/// A fixed set of size-classed subpools holding preallocated `MBuf`s; each
+/// slot's `MBuf` owns its own separate allocation, so a subpool's buffers
+/// are not one contiguous block. Buffers are addressed by the opaque `StoreAddr` returned
+/// from `take`/`add` rather than handed out by value, so a freed slot's
+/// index is reused without any further allocation.
+pub struct MBufPool {
+    subpools: Vec<Subpool>,
+}
+
+impl MBufPool {
+    pub fn new(cfg: PoolCfg) -> Self {
+        let subpools = cfg
+            .buckets
+            .iter()
+            .map(|&(num_buckets, block_size)| Subpool::new(num_buckets, block_size))
+            .collect();
+        MBufPool { subpools }
+    }
+
+    fn subpool_idx_for(&self, len: usize) -> Result<usize, StoreError> {
+        self.subpools
+            .iter()
+            .position(|p| p.block_size >= len)
+            .ok_or(StoreError::DataTooLarge(len))
+    }
 
-        for _ in 0..10 {
-            self.pool.insert(8192, vec![MBuf::new(8192)]);
+    fn slot(&self, addr: StoreAddr) -> Result<(&Subpool, usize), StoreError> {
+        let subpool = self
+            .subpools
+            .get(addr.pool_idx as usize)
+            .ok_or(StoreError::InvalidStoreId)?;
+        let packet_idx = addr.packet_idx as usize;
+        if !*subpool.occupied.get(packet_idx).ok_or(StoreError::InvalidStoreId)? {
+            return Err(StoreError::DataDoesNotExist);
         }
+        Ok((subpool, packet_idx))
+    }
+
+    /// Reserve an empty slot in the smallest subpool whose block size fits
+    /// `size`, returning its address.
+    pub fn take(&mut self, size: usize) -> Result<StoreAddr, StoreError> {
+        let pool_idx = self.subpool_idx_for(size)?;
+        let subpool = &mut self.subpools[pool_idx];
+        let packet_idx = subpool
+            .free_list
+            .pop()
+            .ok_or(StoreError::StoreFull(pool_idx))?;
+        subpool.occupied[packet_idx as usize] = true;
+        subpool.slots[packet_idx as usize].clear();
+        Ok(StoreAddr {
+            pool_idx: pool_idx as u16,
+            packet_idx,
+        })
+    }
+
+    /// Reserve a slot and copy `data` into it in one step.
+    pub fn add(&mut self, data: &[u8]) -> Result<StoreAddr, StoreError> {
+        let addr = self.take(data.len())?;
+        self.modify(addr)?.set_data(data);
+        Ok(addr)
+    }
 
-        for _ in 0..5 {
-            self.pool.insert(16384, vec![MBuf::new(16384)]);
+    pub fn read(&self, addr: StoreAddr) -> Result<&MBuf, StoreError> {
+        let (subpool, packet_idx) = self.slot(addr)?;
+        Ok(&subpool.slots[packet_idx])
+    }
+
+    pub fn modify(&mut self, addr: StoreAddr) -> Result<&mut MBuf, StoreError> {
+        let subpool = self
+            .subpools
+            .get_mut(addr.pool_idx as usize)
+            .ok_or(StoreError::InvalidStoreId)?;
+        let packet_idx = addr.packet_idx as usize;
+        if !*subpool.occupied.get(packet_idx).ok_or(StoreError::InvalidStoreId)? {
+            return Err(StoreError::DataDoesNotExist);
         }
-        for _ in 0..2 {
-            self.pool.insert(32768, vec![MBuf::new(32768)]);
+        Ok(&mut subpool.slots[packet_idx])
+    }
+
+    /// Release `addr`'s slot back to its subpool's free list.
+    pub fn free(&mut self, addr: StoreAddr) -> Result<(), StoreError> {
+        let subpool = self
+            .subpools
+            .get_mut(addr.pool_idx as usize)
+            .ok_or(StoreError::InvalidStoreId)?;
+        let packet_idx = addr.packet_idx as usize;
+        let occupied = subpool
+            .occupied
+            .get_mut(packet_idx)
+            .ok_or(StoreError::InvalidStoreId)?;
+        if !*occupied {
+            return Err(StoreError::DataDoesNotExist);
         }
+        *occupied = false;
+        subpool.slots[packet_idx].clear();
+        subpool.free_list.push(addr.packet_idx);
+        Ok(())
     }
+}
 
-    pub fn take(&mut self, size: usize) -> Option<MBuf> {
-        //adjust size
-        let size = if size < 1024 {
-            1024
-        } else if size < 2048 {
-            2048
-        } else if size < 4096 {
-            4096
-        } else if size < 8192 {
-            8192
-        } else if size < 16384 {
-            16384
-        } else if size < 32768 {
-            32768
-        } else {
-            return None;
-        };
+/// Thread-safe handle to an `MBufPool`. Cloning just bumps the `Arc`
+/// refcount, so every thread that needs to check out buffers can hold its
+/// own handle to the same pool.
+#[derive(Clone)]
+pub struct SharedMBufPool(Arc<Mutex<MBufPool>>);
+
+impl SharedMBufPool {
+    pub fn new(cfg: PoolCfg) -> Self {
+        SharedMBufPool(Arc::new(Mutex::new(MBufPool::new(cfg))))
+    }
+
+    /// Check out a buffer that fits `size`, returning an RAII guard that
+    /// returns it to this pool automatically when dropped.
+    pub fn take(&self, size: usize) -> Result<PooledMBuf, StoreError> {
+        let addr = self.0.lock().unwrap().take(size)?;
+        Ok(PooledMBuf {
+            pool: self.0.clone(),
+            addr,
+        })
+    }
+}
+
+/// A buffer checked out of a `SharedMBufPool`. Derefs to the underlying
+/// `MBuf`; on drop it is cleared and its slot is returned to the pool, so
+/// callers can't forget to give it back.
+pub struct PooledMBuf {
+    pool: Arc<Mutex<MBufPool>>,
+    addr: StoreAddr,
+}
 
-        if let Some(mbufs) = self.pool.get_mut(&size) {
-            return if let Some(buf) = mbufs.pop() {
-                Some(buf)
-            } else {
-                Some(MBuf::new(size))
+impl Deref for PooledMBuf {
+    type Target = MBuf;
+
+    fn deref(&self) -> &MBuf {
+        let pool = self.pool.lock().unwrap();
+        let mbuf = pool
+            .read(self.addr)
+            .expect("a checked-out PooledMBuf's slot stays occupied until Drop");
+        // Safe: `pool`'s subpool storage is preallocated and never
+        // reallocated, and this addr is exclusively ours until Drop frees
+        // it, so the reference stays valid after the lock is released.
+        unsafe { &*(mbuf as *const MBuf) }
+    }
+}
+
+impl DerefMut for PooledMBuf {
+    fn deref_mut(&mut self) -> &mut MBuf {
+        let mut pool = self.pool.lock().unwrap();
+        let mbuf = pool
+            .modify(self.addr)
+            .expect("a checked-out PooledMBuf's slot stays occupied until Drop");
+        // Safe: see the comment in `Deref::deref`.
+        unsafe { &mut *(mbuf as *mut MBuf) }
+    }
+}
+
+impl Drop for PooledMBuf {
+    fn drop(&mut self) {
+        if let Ok(mut pool) = self.pool.lock() {
+            if let Ok(mbuf) = pool.modify(self.addr) {
+                mbuf.clear();
             }
+            let _ = pool.free(self.addr);
         }
-        None
     }
+}
 
-    pub fn give(&mut self, buf: MBuf) {
-        let size = buf.buffer_len;
-        if let Some(mbufs) = self.pool.get_mut(&size) {
-            mbufs.push(buf);
-        }
+/// The allocation backing one or more `SharedBuf` handles. Exists only so
+/// `Backend`'s `Drop` runs once the last `Arc` reference goes away.
+struct Shared {
+    backend: Backend,
+}
+
+/// An immutable, reference-counted view over an `MBuf`'s data, produced by
+/// `MBuf::freeze`. Cloning, `split_to`, and `split_off` all share the same
+/// underlying allocation -- each handle just tracks its own offset and
+/// length -- so carving a large received buffer into many independently
+/// lifetime'd slices costs no copies.
+#[derive(Clone)]
+pub struct SharedBuf {
+    shared: Arc<Shared>,
+    offset: usize,
+    len: usize,
+}
+
+// Safety: `Shared` only ever owns a `Backend`, which is `Send + Sync` (see
+// the impl next to `enum Backend`), and `SharedBuf` only ever hands out
+// read-only slices into it. Handing the same allocation to another thread,
+// or reading it from several threads at once through cloned handles, is as
+// sound as sharing an `Arc<[u8]>` -- which is the whole point of `freeze`.
+unsafe impl Send for SharedBuf {}
+unsafe impl Sync for SharedBuf {}
+
+impl SharedBuf {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.shared.backend.as_ptr().add(self.offset), self.len) }
+    }
+
+    /// Split off the first `at` bytes into a new handle sharing this
+    /// allocation, leaving `self` with the remainder.
+    pub fn split_to(&mut self, at: usize) -> SharedBuf {
+        assert!(at <= self.len, "split_to index out of bounds");
+        let front = SharedBuf {
+            shared: self.shared.clone(),
+            offset: self.offset,
+            len: at,
+        };
+        self.offset += at;
+        self.len -= at;
+        front
+    }
+
+    /// Split off the bytes from `at` onward into a new handle sharing this
+    /// allocation, leaving `self` with the front part.
+    pub fn split_off(&mut self, at: usize) -> SharedBuf {
+        assert!(at <= self.len, "split_off index out of bounds");
+        let back = SharedBuf {
+            shared: self.shared.clone(),
+            offset: self.offset + at,
+            len: self.len - at,
+        };
+        self.len = at;
+        back
+    }
+}
+
+impl Deref for SharedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
     }
 }
 
@@ -190,23 +901,208 @@ mod tests {
        assert_eq!(mbuf.data(), b"hello, world!")
     }
 
+    #[test]
+    fn test_mbuf_grow_preserves_data() {
+        let mut mbuf = MBuf::with_capacity(4);
+        assert_eq!(mbuf.capacity(), 4);
+
+        mbuf.append(b"Hello, world!").unwrap();
+        assert_eq!(mbuf.data(), b"Hello, world!");
+        assert!(mbuf.capacity() >= 13);
+
+        mbuf.reserve(1024);
+        assert!(mbuf.capacity() >= 1024 + mbuf.data().len());
+        assert_eq!(mbuf.data(), b"Hello, world!");
+    }
+
+    #[test]
+    fn test_mbuf_zero_capacity() {
+        let mut mbuf = MBuf::with_capacity(0);
+        assert_eq!(mbuf.capacity(), 0);
+
+        mbuf.append(b"Hello, world!").unwrap();
+        assert_eq!(mbuf.data(), b"Hello, world!");
+    }
+
+    #[test]
+    fn test_mbuf_cursor() {
+        let mut mbuf = MBuf::new(16);
+        mbuf.put_u16(1);
+        mbuf.put_u32(2);
+        mbuf.put_u64(3);
+        mbuf.put_slice(b"tail");
+
+        assert_eq!(mbuf.remaining(), 2 + 4 + 8 + 4);
+        assert_eq!(mbuf.get_u16(), 1);
+        assert_eq!(mbuf.get_u32(), 2);
+        assert_eq!(mbuf.get_u64(), 3);
+
+        let mut tail = [0u8; 4];
+        mbuf.copy_to_slice(&mut tail);
+        assert_eq!(&tail, b"tail");
+        assert_eq!(mbuf.remaining(), 0);
+    }
+
+    #[test]
+    fn test_mbuf_read_write() {
+        let mut mbuf = MBuf::new(8);
+        mbuf.write_all(b"Hello, world!").unwrap();
+
+        let mut out = vec![0u8; 5];
+        mbuf.read_exact(&mut out).unwrap();
+        assert_eq!(&out, b"Hello");
+        assert_eq!(mbuf.remaining(), 8);
+    }
+
+    #[test]
+    fn test_mbuf_headroom_prepend() {
+        let mut mbuf = MBuf::with_headroom(64, 16);
+        assert_eq!(mbuf.headroom(), 16);
+
+        mbuf.append(b"payload").unwrap();
+        assert_eq!(mbuf.data(), b"payload");
+
+        mbuf.prepend(b"hdr:").unwrap();
+        assert_eq!(mbuf.headroom(), 12);
+        assert_eq!(mbuf.data(), b"hdr:payload");
+
+        mbuf.adj(4).unwrap();
+        assert_eq!(mbuf.data(), b"payload");
+        assert_eq!(mbuf.headroom(), 16);
+
+        mbuf.trim(3).unwrap();
+        assert_eq!(mbuf.data(), b"payl");
+
+        assert!(mbuf.prepend(&[0u8; 100]).is_err());
+    }
+
+    #[test]
+    fn test_mbuf_read_write_header() {
+        #[repr(C)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        struct Header {
+            kind: u32,
+            len: u32,
+        }
+        unsafe impl SizeOf for Header {}
+
+        let mut mbuf = MBuf::new(16);
+        let header = Header { kind: 7, len: 42 };
+        mbuf.write_header(&header);
+        mbuf.put_slice(b"body");
+
+        assert_eq!(mbuf.read_header::<Header>(), header);
+        assert_eq!(mbuf.chunk(), b"body");
+    }
+
     #[test]
     fn test_mbuf_pool() {
-        let mut pool = MBufPool::new();
-        pool.initialize();
-
-        let buf1 = pool.take(1024).unwrap();
-        let buf2 = pool.take(2048).unwrap();
-        let buf3 = pool.take(4096).unwrap();
-        let buf4 = pool.take(8192).unwrap();
-        let buf5 = pool.take(16384).unwrap();
-
-        pool.give(buf1);
-        pool.give(buf2);
-
-        let mut buf6 = pool.take(1023).unwrap();
-        assert_eq!(buf6.data(), b"");
-        buf6.append(b"Hello, world!").unwrap();
-        assert_eq!(buf6.data(), b"Hello, world!");
+        let cfg = PoolCfg::new(vec![(2, 1024), (2, 2048), (1, 4096)]);
+        let mut pool = MBufPool::new(cfg);
+
+        let addr1 = pool.add(b"Hello, world!").unwrap();
+        assert_eq!(pool.read(addr1).unwrap().data(), b"Hello, world!");
+
+        // The 4096 subpool only has one slot.
+        pool.take(4096).unwrap();
+        assert!(matches!(pool.take(4096), Err(StoreError::StoreFull(_))));
+
+        // A size bigger than every subpool's block size doesn't fit.
+        assert!(matches!(pool.take(8192), Err(StoreError::DataTooLarge(_))));
+
+        pool.free(addr1).unwrap();
+        assert!(matches!(pool.read(addr1), Err(StoreError::DataDoesNotExist)));
+
+        // The freed slot's index is reused by the next take from that subpool.
+        let addr2 = pool.take(900).unwrap();
+        assert_eq!(addr2, addr1);
+    }
+
+    #[test]
+    fn test_pool_cfg_merges_duplicate_block_sizes() {
+        // Two entries sharing a block size must add up, not shadow each other.
+        let cfg = PoolCfg::new(vec![(5, 1024), (10, 1024)]);
+        let mut pool = MBufPool::new(cfg);
+
+        let mut addrs = Vec::new();
+        for _ in 0..15 {
+            addrs.push(pool.take(1024).unwrap());
+        }
+        assert!(matches!(pool.take(1024), Err(StoreError::StoreFull(_))));
+    }
+
+    #[test]
+    fn test_store_addr_invalid() {
+        let cfg = PoolCfg::new(vec![(1, 1024)]);
+        let mut pool = MBufPool::new(cfg);
+
+        assert!(!INVALID_ADDR.is_valid());
+        assert_eq!(StoreAddr::default(), INVALID_ADDR);
+        assert!(matches!(pool.read(INVALID_ADDR), Err(StoreError::InvalidStoreId)));
+
+        let addr = pool.take(1024).unwrap();
+        assert!(addr.is_valid());
+    }
+
+    #[test]
+    fn test_pooled_mbuf_returns_on_drop() {
+        let cfg = PoolCfg::new(vec![(1, 1024)]);
+        let pool = SharedMBufPool::new(cfg);
+
+        {
+            let mut buf = pool.take(1024).unwrap();
+            buf.append(b"Hello, world!").unwrap();
+            assert_eq!(buf.data(), b"Hello, world!");
+        }
+
+        // The single slot was cleared and freed when `buf` dropped, so it's
+        // available again -- and empty.
+        let buf = pool.take(1024).unwrap();
+        assert_eq!(buf.data(), b"");
+    }
+
+    #[test]
+    fn test_mbuf_freeze_and_split() {
+        let mut mbuf = MBuf::new(16);
+        mbuf.append(b"Hello, world!").unwrap();
+
+        let mut shared = mbuf.freeze();
+        assert_eq!(&shared[..], b"Hello, world!");
+
+        let hello = shared.split_to(5);
+        assert_eq!(&hello[..], b"Hello");
+        assert_eq!(&shared[..], b", world!");
+
+        let bang = shared.split_off(2);
+        assert_eq!(&shared[..], b", ");
+        assert_eq!(&bang[..], b"world!");
+
+        // Clones share the same allocation and don't copy.
+        let bang2 = bang.clone();
+        assert_eq!(&bang2[..], b"world!");
+    }
+
+    #[test]
+    fn test_shared_buf_crosses_threads() {
+        let mut mbuf = MBuf::new(16);
+        mbuf.append(b"Hello, world!").unwrap();
+        let shared = mbuf.freeze();
+
+        let handle = std::thread::spawn(move || shared[..].to_vec());
+        assert_eq!(handle.join().unwrap(), b"Hello, world!");
+    }
+
+    #[test]
+    fn test_mbuf_with_backend() {
+        let heap = MBuf::with_backend(128, BackendKind::Heap);
+        assert_eq!(heap.buffer_len(), 128);
+
+        let mapped = MBuf::with_backend(MMAP_THRESHOLD, BackendKind::Mmap);
+        assert_eq!(mapped.buffer_len(), MMAP_THRESHOLD);
+
+        // Buffers at or above the threshold pick mmap automatically.
+        let auto = MBuf::new(MMAP_THRESHOLD);
+        assert!(matches!(auto.backend, Backend::Mmap(_)));
+        assert_eq!(auto.buffer_len(), MMAP_THRESHOLD);
     }
 }